@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+structopt::clap::arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum Action {
+        Delete,
+        Hardlink,
+        Symlink,
+    }
+}
+
+structopt::clap::arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum KeepPolicy {
+        First,
+        Oldest,
+        Newest,
+        ShortestPath,
+    }
+}
+
+#[cfg(unix)]
+fn make_symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn make_symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+fn keeper_index(keep: KeepPolicy, entries: &[PathBuf]) -> usize {
+    match keep {
+        // `entries`' order reflects whichever worker thread's hash job
+        // happened to finish first, not path discovery order, so "first"
+        // has to mean something stable: lexically smallest path.
+        KeepPolicy::First => {
+            entries.iter().enumerate()
+                .min_by(|(_, a), (_, b)| a.cmp(b))
+                .map(|(i, _)| i)
+                .unwrap_or(0)
+        }
+        KeepPolicy::ShortestPath => {
+            entries.iter().enumerate()
+                .min_by_key(|(_, p)| p.as_os_str().len())
+                .map(|(i, _)| i)
+                .unwrap_or(0)
+        }
+        KeepPolicy::Oldest => extreme_mtime_index(entries, false),
+        KeepPolicy::Newest => extreme_mtime_index(entries, true),
+    }
+}
+
+fn extreme_mtime_index(entries: &[PathBuf], newest: bool) -> usize {
+    let mtimes = entries.iter().map(|p| fs::metadata(p).and_then(|m| m.modified()).ok());
+    let mut best: Option<(usize, std::time::SystemTime)> = None;
+    for (i, mtime) in mtimes.enumerate() {
+        if let Some(mtime) = mtime {
+            let better = match best {
+                None => true,
+                Some((_, best_time)) => if newest { mtime > best_time } else { mtime < best_time },
+            };
+            if better {
+                best = Some((i, mtime));
+            }
+        }
+    }
+    best.map(|(i, _)| i).unwrap_or(0)
+}
+
+// Writes `make`'s output alongside `victim` and renames over it, so a
+// process crash never leaves `victim` half-written or missing.
+fn replace_atomically(victim: &Path, make: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let dir = victim.parent().unwrap_or_else(|| Path::new("."));
+    let tmp = dir.join(format!(".fddf-tmp-{}", std::process::id()));
+    make(&tmp)?;
+    fs::rename(&tmp, victim)
+}
+
+// Applies `action` to every member of `entries` except the one kept under
+// `keep`'s policy. Without `force`, only prints what would be done.
+pub fn handle_dupe(action: Action, keep: KeepPolicy, force: bool, entries: &[PathBuf]) {
+    let keep_idx = keeper_index(keep, entries);
+    let keeper = &entries[keep_idx];
+    for (i, victim) in entries.iter().enumerate() {
+        if i == keep_idx {
+            continue;
+        }
+        match action {
+            Action::Delete => {
+                if force {
+                    if let Err(e) = fs::remove_file(victim) {
+                        eprintln!("Error deleting {}: {}", victim.display(), e);
+                    }
+                } else {
+                    println!("Would delete {}", victim.display());
+                }
+            }
+            Action::Hardlink => {
+                if force {
+                    if let Err(e) = replace_atomically(victim, |tmp| fs::hard_link(keeper, tmp)) {
+                        eprintln!("Error hardlinking {}: {}", victim.display(), e);
+                    }
+                } else {
+                    println!("Would hardlink {} -> {}", victim.display(), keeper.display());
+                }
+            }
+            Action::Symlink => {
+                if force {
+                    // A symlink's target is resolved relative to the link's
+                    // own directory, not the process cwd, so write an
+                    // absolute path regardless of how `keeper` was spelled.
+                    let result = keeper.canonicalize()
+                        .and_then(|abs| replace_atomically(victim, |tmp| make_symlink(&abs, tmp)));
+                    if let Err(e) = result {
+                        eprintln!("Error symlinking {}: {}", victim.display(), e);
+                    }
+                } else {
+                    println!("Would symlink {} -> {}", victim.display(), keeper.display());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeper_index_first_is_lexical_regardless_of_entry_order() {
+        let entries = vec![PathBuf::from("/b/file"), PathBuf::from("/a/file")];
+        assert_eq!(keeper_index(KeepPolicy::First, &entries), 1);
+    }
+
+    #[test]
+    fn keeper_index_shortest_path() {
+        let entries = vec![PathBuf::from("/a/long/path/file"), PathBuf::from("/short")];
+        assert_eq!(keeper_index(KeepPolicy::ShortestPath, &entries), 1);
+    }
+
+    #[test]
+    fn extreme_mtime_index_picks_oldest_and_newest() {
+        let dir = std::env::temp_dir().join(format!("fddf-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let older = dir.join("older");
+        let newer = dir.join("newer");
+        fs::write(&older, b"a").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(&newer, b"b").unwrap();
+
+        let entries = vec![older.clone(), newer.clone()];
+        assert_eq!(extreme_mtime_index(&entries, false), 0);
+        assert_eq!(extreme_mtime_index(&entries, true), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
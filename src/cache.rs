@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+use serde::{Deserialize, Serialize};
+
+// Identifies a file across runs without reading its contents.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    #[cfg(unix)]
+    dev: u64,
+    #[cfg(unix)]
+    ino: u64,
+    #[cfg(not(unix))]
+    path: PathBuf,
+    size: u64,
+    mtime_nanos: i64,
+}
+
+impl CacheKey {
+    #[cfg(unix)]
+    pub fn for_file(_path: &Path, meta: &Metadata) -> io::Result<CacheKey> {
+        Ok(CacheKey {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.len(),
+            mtime_nanos: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn for_file(path: &Path, meta: &Metadata) -> io::Result<CacheKey> {
+        let mtime_nanos = meta.modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or(0);
+        Ok(CacheKey { path: path.canonicalize()?, size: meta.len(), mtime_nanos })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    path: PathBuf,
+    algo: u8,
+    hash: Vec<u8>,
+}
+
+// A `(dev, ino, size, mtime) -> hash` map, persisted to a single file.
+#[derive(Default, Serialize, Deserialize)]
+pub struct HashCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+}
+
+impl HashCache {
+    // Starts an empty cache if `path` doesn't exist or can't be parsed
+    // (e.g. written by an incompatible version).
+    pub fn load(path: &Path) -> HashCache {
+        std::fs::read(path)
+            .ok()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default()
+    }
+
+    // Only a hit if it was computed with the same algorithm we're running now.
+    pub fn get(&self, key: &CacheKey, algo: u8) -> Option<Vec<u8>> {
+        self.entries.get(key).filter(|e| e.algo == algo).map(|e| e.hash.clone())
+    }
+
+    pub fn insert(&mut self, key: CacheKey, path: PathBuf, algo: u8, hash: Vec<u8>) {
+        self.entries.insert(key, CacheEntry { path, algo, hash });
+    }
+
+    // Drops entries whose file has actually changed or vanished since it was
+    // cached, by re-stat'ing it and recomputing its key. Entries for files
+    // this run never walked (outside its roots or filters) are left alone.
+    pub fn prune_stale(&mut self) {
+        self.entries.retain(|key, entry| {
+            fs::metadata(&entry.path)
+                .ok()
+                .and_then(|meta| CacheKey::for_file(&entry.path, &meta).ok())
+                .map_or(false, |fresh| &fresh == key)
+        });
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = bincode::serialize(&self.entries).expect("cache serialization cannot fail");
+        std::fs::write(path, data)
+    }
+}
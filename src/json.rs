@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+structopt::clap::arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum JsonFormat {
+        Ndjson,
+        Array,
+    }
+}
+
+#[derive(Serialize)]
+pub struct DupeGroupRecord {
+    pub size: u64,
+    pub wasted_bytes: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Serialize)]
+pub struct SummaryRecord {
+    pub total_dupes: u64,
+    pub total_files: u64,
+    pub total_size: u64,
+}
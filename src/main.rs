@@ -3,47 +3,148 @@ use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::sync::mpsc::{Sender, channel};
 use std::collections::hash_map::Entry;
+use std::time::Instant;
 use structopt::StructOpt;
 use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
-use blake3::Hasher;
+use blake3::Hasher as Blake3Hasher;
 use walkdir::{DirEntry, WalkDir};
 #[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 use regex::Regex;
 use glob::Pattern;
+use xxhash_rust::xxh3::Xxh3;
+use crc32fast::Hasher as Crc32State;
+
+mod cache;
+use cache::{CacheKey, HashCache};
+mod actions;
+use actions::{Action, KeepPolicy};
+mod stats;
+use stats::Stats;
+mod json;
+use json::JsonFormat;
 
 fn err(path: &PathBuf, err: io::Error) {
     eprintln!("Error processing file {}: {}", path.display(), err);
 }
 
-type HashSender = Sender<(u64, PathBuf, Vec<u8>)>;
+// The trailing `Option<CacheKey>` lets the collector thread write freshly
+// computed hashes back into the cache; it is `None` for results that came
+// from the cache in the first place.
+type HashSender = Sender<(u64, PathBuf, Vec<u8>, Option<CacheKey>)>;
+// The `Metadata` rides along so the next stage (the full sampling hash) can
+// build a cache key without re-`stat`ing the file.
+type HeadHashSender = Sender<(u64, PathBuf, Metadata, Vec<u8>)>;
 type DupeSender = Sender<(u64, Vec<PathBuf>)>;
 
 const BLOCKSIZE: usize = 4096;
 const GAPSIZE: i64 = 102_400;
 
-fn hash_file_inner(path: &PathBuf) -> io::Result<Vec<u8>> {
+structopt::clap::arg_enum! {
+    // The hash algorithm used for the sampling hash that groups same-size
+    // files before the final byte-by-byte comparison.
+    #[derive(Debug, Clone, Copy)]
+    pub enum HashAlgo {
+        Blake3,
+        Xxh3,
+        Crc32,
+    }
+}
+
+// Abstracts over the digest state of the hash algorithms we support, so
+// that `hash_file_inner` doesn't need to care which one was picked.
+trait FileDigest {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self) -> Vec<u8>;
+}
+
+impl FileDigest for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        Blake3Hasher::update(self, data);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+impl FileDigest for Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        Xxh3::update(self, data);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.digest().to_le_bytes().to_vec()
+    }
+}
+
+impl FileDigest for Crc32State {
+    fn update(&mut self, data: &[u8]) {
+        Crc32State::update(self, data);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.finalize().to_le_bytes().to_vec()
+    }
+}
+
+fn hash_file_with<D: FileDigest>(path: &PathBuf, mut digest: D, stats: &Stats) -> io::Result<Vec<u8>> {
     let mut buf = [0u8; BLOCKSIZE];
     let mut fp = File::open(&path)?;
-    let mut digest = Hasher::new();
     // When we compare byte-by-byte, we don't need to hash the whole file.
     // Instead, hash a block of 4kB, skipping 100kB.
     loop {
         match fp.read(&mut buf)? {
             0 => break,
-            n => digest.update(&buf[..n]),
+            n => {
+                digest.update(&buf[..n]);
+                Stats::add(&stats.hash_bytes_read, n as u64);
+            }
         };
         fp.seek(SeekFrom::Current(GAPSIZE))?;
     }
-    Ok(digest.finalize().as_bytes().to_vec())
+    Ok(digest.finish())
 }
 
-fn hash_file(verbose: bool, fsize: u64, path: PathBuf, tx: HashSender) {
+fn hash_file_inner(path: &PathBuf, algo: HashAlgo, stats: &Stats) -> io::Result<Vec<u8>> {
+    match algo {
+        HashAlgo::Blake3 => hash_file_with(path, Blake3Hasher::new(), stats),
+        HashAlgo::Xxh3 => hash_file_with(path, Xxh3::new(), stats),
+        HashAlgo::Crc32 => hash_file_with(path, Crc32State::new(), stats),
+    }
+}
+
+fn hash_file(verbose: bool, algo: HashAlgo, fsize: u64, path: PathBuf, cache_key: Option<CacheKey>, tx: HashSender, stats: &Stats) {
     if verbose {
         eprintln!("Hashing {}...", path.display());
     }
-    match hash_file_inner(&path) {
-        Ok(hash) => tx.send((fsize, path, hash)).unwrap(),
+    match hash_file_inner(&path, algo, stats) {
+        Ok(hash) => tx.send((fsize, path, hash, cache_key)).unwrap(),
+        Err(e) => err(&path, e),
+    }
+}
+
+// Files differing in their very first block are provably distinct, so a
+// single unseeked read of it lets us split a same-size group cheaply before
+// paying for the full strided sampling hash below. The algorithm used here
+// is fixed (not the user's `--hash` choice): a collision only costs an
+// extra, still-cheap, sampling hash, never a wrong result.
+fn head_hash_file_inner(path: &PathBuf, stats: &Stats) -> io::Result<Vec<u8>> {
+    let mut buf = [0u8; BLOCKSIZE];
+    let mut fp = File::open(path)?;
+    let n = fp.read(&mut buf)?;
+    Stats::add(&stats.hash_bytes_read, n as u64);
+    let mut digest = Xxh3::new();
+    digest.update(&buf[..n]);
+    Ok(digest.digest().to_le_bytes().to_vec())
+}
+
+fn head_hash_file(verbose: bool, fsize: u64, path: PathBuf, meta: Metadata, tx: HeadHashSender, stats: &Stats) {
+    if verbose {
+        eprintln!("Head-hashing {}...", path.display());
+    }
+    match head_hash_file_inner(&path, stats) {
+        Ok(hash) => tx.send((fsize, path, meta, hash)).unwrap(),
         Err(e) => err(&path, e),
     }
 }
@@ -106,7 +207,7 @@ impl Candidate for SlowCandidate {
     }
 }
 
-fn compare_files_inner<C: Candidate>(fsize: u64, mut todo: Vec<C>, tx: &DupeSender) {
+fn compare_files_inner<C: Candidate>(fsize: u64, mut todo: Vec<C>, tx: &DupeSender, stats: &Stats) {
     'outer: loop {
         // Collect all candidates where buffer differs from the first.
         let mut todo_diff = Vec::new();
@@ -119,7 +220,7 @@ fn compare_files_inner<C: Candidate>(fsize: u64, mut todo: Vec<C>, tx: &DupeSend
         if todo_diff.len() >= 2 {
             // Note that they will compare their current buffer again as
             // the first step, which is exactly what we want.
-            compare_files_inner(fsize, todo_diff, tx);
+            compare_files_inner(fsize, todo_diff, tx, stats);
         }
         // If there are not enough candidates left, no dupes.
         if todo.len() < 2 {
@@ -132,7 +233,7 @@ fn compare_files_inner<C: Candidate>(fsize: u64, mut todo: Vec<C>, tx: &DupeSend
                 Ok(0) => break 'outer,
                 // If an error occurs, do not process this file further.
                 Err(_) => { todo.remove(i); }
-                _ => ()
+                Ok(n) => Stats::add(&stats.compare_bytes_read, n as u64),
             }
         }
     }
@@ -140,7 +241,7 @@ fn compare_files_inner<C: Candidate>(fsize: u64, mut todo: Vec<C>, tx: &DupeSend
     tx.send((fsize, todo.into_iter().map(Candidate::into_path).collect())).unwrap();
 }
 
-fn compare_files(verbose: bool, fsize: u64, paths: Vec<PathBuf>, tx: DupeSender) {
+fn compare_files(verbose: bool, fsize: u64, paths: Vec<PathBuf>, tx: DupeSender, stats: &Stats) {
     if verbose {
         for path in &paths {
             eprintln!("Comparing {}...", path.display());
@@ -155,12 +256,12 @@ fn compare_files(verbose: bool, fsize: u64, paths: Vec<PathBuf>, tx: DupeSender)
                 Err(e) => { err(&p, e); None }
             }
         }).collect();
-        compare_files_inner(fsize, todo, &tx);
+        compare_files_inner(fsize, todo, &tx, stats);
     } else {
         let todo = paths.into_iter().map(|p| {
             SlowCandidate { path: p, pos: 0, buf: [0u8; BLOCKSIZE], n: 0 }
         }).collect();
-        compare_files_inner(fsize, todo, &tx);
+        compare_files_inner(fsize, todo, &tx, stats);
     }
 }
 
@@ -189,6 +290,36 @@ struct Args {
     pattern: Option<Pattern>,
     #[structopt(short="F", help="Check only filenames matching this regexp", group="patterns")]
     regexp: Option<Regex>,
+    #[structopt(long="exclude", number_of_values=1,
+                help="Don't descend into directories matching this glob (repeatable)")]
+    exclude: Vec<Pattern>,
+    #[structopt(long="ext", parse(from_str=parse_ext_list),
+                help="Only include files with one of these comma-separated extensions")]
+    ext: Option<Vec<String>>,
+    #[structopt(long="not-ext", parse(from_str=parse_ext_list),
+                help="Exclude files with one of these comma-separated extensions")]
+    not_ext: Option<Vec<String>>,
+    #[structopt(short="a", long="hash", default_value="Xxh3", possible_values=&HashAlgo::variants(),
+                case_insensitive=true, help="Hash algorithm used for the sampling hash")]
+    hash: HashAlgo,
+    #[structopt(long="cache", parse(from_os_str),
+                help="Cache file for sampling hashes, to skip rehashing unchanged files")]
+    cache: Option<PathBuf>,
+    #[structopt(long="action", possible_values=&Action::variants(), case_insensitive=true,
+                help="Replace duplicates found with delete/hardlink/symlink (dry-run unless --force)")]
+    action: Option<Action>,
+    #[structopt(long="keep", default_value="First", possible_values=&KeepPolicy::variants(),
+                case_insensitive=true, help="Which member of a duplicate group --action keeps untouched")]
+    keep: KeepPolicy,
+    #[structopt(long="force", help="Actually perform --action instead of just printing the plan")]
+    force: bool,
+    #[structopt(long="stats", help="Report per-stage counters and timings")]
+    stats: bool,
+    #[structopt(long="json", help="Emit duplicate groups as JSON instead of human-readable text")]
+    json: bool,
+    #[structopt(long="json-format", default_value="Ndjson", possible_values=&JsonFormat::variants(),
+                case_insensitive=true, help="With --json, groups as one object per line, or as a single array; a summary object follows on its own line")]
+    json_format: JsonFormat,
     #[structopt(help="Root directory or directories to search")]
     roots: Vec<PathBuf>,
 }
@@ -200,10 +331,24 @@ fn is_hidden_file(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
+fn parse_ext_list(s: &str) -> Vec<String> {
+    s.split(',').map(|e| e.trim().to_lowercase()).collect()
+}
+
+fn has_extension(entry: &DirEntry, exts: &[String]) -> bool {
+    entry.path().extension()
+        .and_then(|e| e.to_str())
+        .map(|e| exts.iter().any(|want| want.eq_ignore_ascii_case(e)))
+        .unwrap_or(false)
+}
+
 fn main() {
     let Args { minsize, maxsize, verbose, singleline, grandtotal, nohidden,
-               nonrecursive, nul, pattern, regexp, roots } = Args::from_args();
+               nonrecursive, nul, pattern, regexp, exclude, ext, not_ext, hash, cache: cache_path,
+               action, keep, force, stats: report_stats, json, json_format, roots } = Args::from_args();
+    let stats = Stats::default();
     let maxsize = maxsize.unwrap_or(u64::max_value());
+    let mut hash_cache = cache_path.as_ref().map(|p| HashCache::load(p));
 
     enum Select {
         Pattern(Pattern),
@@ -227,10 +372,29 @@ fn main() {
         Select::Regex(ref r) => entry.file_name().to_str().map_or(false, |f| r.is_match(f)),
     };
 
+    let matches_ext = |entry: &DirEntry| {
+        ext.as_deref().map_or(true, |allow| has_extension(entry, allow))
+            && !not_ext.as_deref().map_or(false, |deny| has_extension(entry, deny))
+    };
+
+    // Pruning excluded directories at the `WalkDir` level means their whole
+    // subtree costs nothing, rather than rejecting each file inside it.
+    // Matched against the directory name alone, like -f/-F match file names,
+    // so e.g. `--exclude target` prunes every nested `target/`, not just one
+    // sitting directly under a root.
+    let is_excluded_dir = |entry: &DirEntry| {
+        entry.file_type().is_dir()
+            && entry.file_name().to_str().map_or(false, |name| exclude.iter().any(|pat| pat.matches(name)))
+    };
+
     // See below for these maps' purpose.
     let mut sizes = HashMap::default();
+    let mut head_hashes = HashMap::default();
     let mut hashes = HashMap::default();
     let mut inodes = HashSet::default();
+    // Hashes computed (as opposed to served from the cache) during this run,
+    // to be written back into the cache once the walk is done.
+    let mut new_cache_entries: Vec<(CacheKey, PathBuf, Vec<u8>)> = Vec::new();
 
     // We take care to avoid visiting a single inode twice,
     // which takes care of (false positive) hardlinks.
@@ -247,43 +411,50 @@ fn main() {
     // found to be a good pool size, likely since the walker thread should be
     // doing mostly IO.
     let pool = scoped_pool::Pool::new(num_cpus::get() + 1);
+    let walk_and_hash_start = Instant::now();
+    let mut walk_time = std::time::Duration::default();
     pool.scoped(|scope| {
-        let (tx, rx) = channel();
+        let (head_tx, head_rx) = channel();
 
-        // One long-living job to collect hashes and populate the "hashes"
-        // hashmap, received from the hashing jobs.  Only hashmap entries
-        // with more than one vector element are duplicates in the end.
-        let hashref = &mut hashes;
+        // One long-living job to collect head hashes and populate the
+        // "head_hashes" hashmap, received from the head-hashing jobs.
+        let head_hashes_ref = &mut head_hashes;
         scope.execute(move || {
-            for (size, path, hash) in rx.iter() {
-                hashref.entry((size, hash)).or_insert_with(Vec::new).push(path);
+            for (size, path, meta, hhash) in head_rx.iter() {
+                head_hashes_ref.entry((size, hhash)).or_insert_with(Vec::new).push((path, meta));
             }
         });
 
         enum Found {
-            One(PathBuf),
+            One(PathBuf, Metadata),
             Multiple
         }
 
+        // Submits the cheap head-hashing of a single file, found to share
+        // its size with at least one other file.
+        let submit_head = |fsize: u64, path: PathBuf, meta: Metadata| {
+            let txc = head_tx.clone();
+            let statsref = &stats;
+            scope.execute(move || head_hash_file(verbose, fsize, path, meta, txc, statsref));
+        };
+
         // Processing a single file entry, with the "sizes" hashmap collecting
         // same-size files.  Entries are either Found::One or Found::Multiple,
-        // so that we can submit the first file's path as a hashing job when the
-        // first duplicate is found.  Hashing each file is submitted as a job to
-        // the pool.
-        let mut process = |fsize, dir_entry: DirEntry| {
+        // so that we can submit the first file's path as a head-hashing job
+        // when the first duplicate is found.
+        let mut process = |fsize, dir_entry: DirEntry, meta: Metadata| {
             let path = dir_entry.path().to_path_buf();
             match sizes.entry(fsize) {
                 Entry::Vacant(v) => {
-                    v.insert(Found::One(path));
+                    v.insert(Found::One(path, meta));
                 }
                 Entry::Occupied(mut v) => {
                     let first = std::mem::replace(v.get_mut(), Found::Multiple);
-                    if let Found::One(first_path) = first {
-                        let txc = tx.clone();
-                        scope.execute(move || hash_file(verbose, fsize, first_path, txc));
+                    if let Found::One(first_path, first_meta) = first {
+                        Stats::add(&stats.size_groups_hashed, 1);
+                        submit_head(fsize, first_path, first_meta);
                     }
-                    let txc = tx.clone();
-                    scope.execute(move || hash_file(verbose, fsize, path, txc));
+                    submit_head(fsize, path, meta);
                 }
             }
         };
@@ -297,19 +468,22 @@ fn main() {
             } else {
                 WalkDir::new(root).follow_links(false)
             };
+            let walkdir = walkdir.into_iter().filter_entry(|e| !is_excluded_dir(e));
             for dir_entry in walkdir {
                 match dir_entry {
                     Ok(dir_entry) => {
                         if dir_entry.file_type().is_file() {
+                            Stats::add(&stats.files_considered, 1);
                             match dir_entry.metadata() {
                                 Ok(meta) => {
                                     let fsize = meta.len();
-                                    if fsize >= minsize && fsize <= maxsize {
-                                        if check_inode(&mut inodes, &meta) {
-                                            if !hidden_excluded(&dir_entry) && matches_pattern(&dir_entry) {
-                                                process(fsize, dir_entry);
-                                            }
-                                        }
+                                    if fsize >= minsize && fsize <= maxsize
+                                        && check_inode(&mut inodes, &meta)
+                                        && !hidden_excluded(&dir_entry) && matches_pattern(&dir_entry)
+                                        && matches_ext(&dir_entry) {
+                                        process(fsize, dir_entry, meta);
+                                    } else {
+                                        Stats::add(&stats.files_skipped, 1);
                                     }
                                 }
                                 Err(e) => {
@@ -324,11 +498,73 @@ fn main() {
                 }
             }
         }
+        walk_time = walk_and_hash_start.elapsed();
+    });
+
+    // Of each same-size group, only the sub-groups whose head hash still
+    // collides are worth the full, more expensive sampling hash; a group of
+    // one is provably unique and needs neither that hash nor a compare.
+    pool.scoped(|scope| {
+        let (tx, rx) = channel();
+
+        // One long-living job to collect hashes and populate the "hashes"
+        // hashmap, received from the hashing jobs.  Only hashmap entries
+        // with more than one vector element are duplicates in the end.
+        // Freshly computed hashes (those carrying a cache key) are also
+        // queued up for writing back into the hash cache.
+        let hashref = &mut hashes;
+        let new_cache_entries_ref = &mut new_cache_entries;
+        scope.execute(move || {
+            for (size, path, fhash, cache_key) in rx.iter() {
+                if let Some(key) = cache_key {
+                    new_cache_entries_ref.push((key, path.clone(), fhash.clone()));
+                }
+                hashref.entry((size, fhash)).or_insert_with(Vec::new).push(path);
+            }
+        });
+
+        for ((fsize, _), members) in head_hashes {
+            if members.len() < 2 {
+                continue;
+            }
+            for (path, meta) in members {
+                // Submits (or short-circuits via the cache) the full sampling
+                // hash of a single file surviving the head-hash prefilter.
+                let cache_key = hash_cache.as_ref().and_then(|_| CacheKey::for_file(&path, &meta).ok());
+                let cached = cache_key.as_ref()
+                    .and_then(|key| hash_cache.as_ref().and_then(|c| c.get(key, hash as u8)));
+                if let Some(cached_hash) = cached {
+                    tx.send((fsize, path, cached_hash, None)).unwrap();
+                } else {
+                    let txc = tx.clone();
+                    let statsref = &stats;
+                    scope.execute(move || hash_file(verbose, hash, fsize, path, cache_key, txc, statsref));
+                }
+            }
+        }
     });
+    // The scoped pools only return once every queued job (and the collector
+    // that drains it) has finished, so the time since the walk finished is a
+    // reasonable proxy for time spent head-hashing and sampling-hashing.
+    let hash_time = walk_and_hash_start.elapsed().saturating_sub(walk_time);
+
+    if let Some(cache) = hash_cache.as_mut() {
+        for (key, path, file_hash) in new_cache_entries {
+            cache.insert(key, path, hash as u8, file_hash);
+        }
+        cache.prune_stale();
+        if let Some(cache_path) = &cache_path {
+            if let Err(e) = cache.save(cache_path) {
+                eprintln!("Error writing hash cache {}: {}", cache_path.display(), e);
+            }
+        }
+    }
 
     let mut total_dupes = 0;
     let mut total_files = 0;
     let mut total_size = 0;
+    let mut json_groups: Vec<json::DupeGroupRecord> = Vec::new();
+    let compare_time;
 
     {
         // Present results to the user.
@@ -336,6 +572,21 @@ fn main() {
             total_dupes += 1;
             total_files += entries.len() - 1;
             total_size += size * (entries.len() - 1) as u64;
+            if let Some(action) = action {
+                actions::handle_dupe(action, keep, force, &entries);
+            }
+            if json {
+                let record = json::DupeGroupRecord {
+                    size,
+                    wasted_bytes: size * (entries.len() - 1) as u64,
+                    paths: entries,
+                };
+                match json_format {
+                    JsonFormat::Ndjson => writeln!(out, "{}", serde_json::to_string(&record).unwrap()).unwrap(),
+                    JsonFormat::Array => json_groups.push(record),
+                }
+                return;
+            }
             if singleline {
                 let last = entries.len() - 1;
                 for (i, path) in entries.into_iter().enumerate() {
@@ -364,6 +615,7 @@ fn main() {
 
         // Compare files with matching hashes byte-by-byte, using the same thread
         // pool strategy as above.
+        let compare_start = Instant::now();
         pool.scoped(|scope| {
             let (tx, rx) = channel();
 
@@ -379,18 +631,35 @@ fn main() {
             // Compare found files with same size and hash byte-by-byte.
             for ((fsize, _), entries) in hashes {
                 if entries.len() > 1 {
+                    Stats::add(&stats.hash_groups_compared, 1);
                     let txc = tx.clone();
-                    scope.execute(move || compare_files(verbose, fsize, entries, txc));
+                    let statsref = &stats;
+                    scope.execute(move || compare_files(verbose, fsize, entries, txc, statsref));
                 }
             }
         });
+        compare_time = compare_start.elapsed();
     }
 
-    if grandtotal {
+    if json {
+        if let JsonFormat::Array = json_format {
+            println!("{}", serde_json::to_string(&json_groups).unwrap());
+        }
+        let summary = json::SummaryRecord {
+            total_dupes: total_dupes as u64,
+            total_files: total_files as u64,
+            total_size,
+        };
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    } else if grandtotal {
         println!("Overall results:");
         println!("    {} groups of duplicate files", total_dupes);
         println!("    {} files are duplicates", total_files);
         let (val, suffix) = unbytify::bytify(total_size);
         println!("    {:.1} {} of space taken by duplicates", val, suffix);
     }
+
+    if report_stats {
+        stats.report(walk_time, hash_time, compare_time);
+    }
 }
@@ -0,0 +1,37 @@
+// Counters and stage timings for the `--stats` report, updated from
+// whichever thread is doing the relevant work.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Stats {
+    pub files_considered: AtomicU64,
+    pub files_skipped: AtomicU64,
+    pub hash_bytes_read: AtomicU64,
+    pub compare_bytes_read: AtomicU64,
+    pub size_groups_hashed: AtomicU64,
+    pub hash_groups_compared: AtomicU64,
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, walk_time: Duration, hash_time: Duration, compare_time: Duration) {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        println!("Stats:");
+        println!("    {:<32} {}", "files considered:", load(&self.files_considered));
+        println!("    {:<32} {}", "files skipped by filters:", load(&self.files_skipped));
+        println!("    {:<32} {}", "size-collision groups hashed:", load(&self.size_groups_hashed));
+        println!("    {:<32} {}", "hash groups compared:", load(&self.hash_groups_compared));
+        let (val, suffix) = unbytify::bytify(load(&self.hash_bytes_read));
+        println!("    {:<32} {:.1} {}", "bytes read while hashing:", val, suffix);
+        let (val, suffix) = unbytify::bytify(load(&self.compare_bytes_read));
+        println!("    {:<32} {:.1} {}", "bytes read while comparing:", val, suffix);
+        println!("    {:<32} {:.2}s", "time spent walking:", walk_time.as_secs_f64());
+        println!("    {:<32} {:.2}s", "time spent hashing:", hash_time.as_secs_f64());
+        println!("    {:<32} {:.2}s", "time spent comparing:", compare_time.as_secs_f64());
+    }
+}